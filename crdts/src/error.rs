@@ -0,0 +1,98 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Something went wrong reading or writing project state. The variants let callers decide
+// whether it's worth retrying (or telling the user to check their environment) or whether
+// the file itself needs to be fixed or thrown away.
+#[derive(Debug)]
+pub enum Error {
+    // The filesystem (or the platform APIs around it) misbehaved: a disk full, a missing
+    // permission, a directory we couldn't determine. This is transient in the sense that
+    // the data isn't necessarily bad - running again, or in a different environment, might
+    // just work.
+    Io { path: PathBuf, source: io::Error },
+    // A file was there and readable, but what came out of it wasn't valid - a corrupt
+    // `.pennyop`, a `keys.json` that won't decrypt, a folder name that isn't a public key.
+    // Retrying won't help; the file needs to be fixed, replaced, or skipped.
+    Corrupt { path: PathBuf, reason: String },
+    // Another process already holds the lock on `path`. Transient in the same sense as
+    // `Io`: there's nothing wrong with the data, the lock just needs to clear.
+    Locked { path: PathBuf, reason: String },
+}
+
+impl Error {
+    pub fn io(path: impl AsRef<Path>, source: io::Error) -> Error {
+        Error::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn corrupt(path: impl AsRef<Path>, reason: impl Into<String>) -> Error {
+        Error::Corrupt {
+            path: path.as_ref().to_path_buf(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn locked(path: impl AsRef<Path>, reason: impl Into<String>) -> Error {
+        Error::Locked {
+            path: path.as_ref().to_path_buf(),
+            reason: reason.into(),
+        }
+    }
+
+    // Whether this is worth retrying (or reporting as an environment problem), as opposed
+    // to a file that's genuinely corrupt and needs human attention.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Io { .. } | Error::Locked { .. })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.to_string_lossy(), source),
+            Error::Corrupt { path, reason } => {
+                write!(f, "{} is corrupt: {}", path.to_string_lossy(), reason)
+            }
+            Error::Locked { path, reason } => {
+                write!(f, "{}: {}", path.to_string_lossy(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_and_locked_are_transient() {
+        let io_err = Error::io("keys.json", io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert!(io_err.is_transient());
+
+        let locked_err = Error::locked(".lock", "already in use");
+        assert!(locked_err.is_transient());
+    }
+
+    #[test]
+    fn corrupt_is_not_transient() {
+        let err = Error::corrupt("keys.json", "wrong passphrase");
+        assert!(!err.is_transient());
+    }
+
+    #[test]
+    fn display_includes_path_and_reason() {
+        let err = Error::corrupt("keys.json", "wrong passphrase");
+        assert_eq!(err.to_string(), "keys.json is corrupt: wrong passphrase");
+
+        let err = Error::locked(".lock", "already in use");
+        assert_eq!(err.to_string(), ".lock: already in use");
+    }
+}
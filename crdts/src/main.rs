@@ -1,20 +1,29 @@
 use base64::{CharacterSet, Config};
+use clap::{Parser, Subcommand};
 use directories_next::ProjectDirs;
+use fs2::FileExt;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use sodiumoxide::crypto::hash;
+use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::secretbox;
 use sodiumoxide::crypto::sign;
 use std::collections::HashMap;
-use std::env;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
+use std::io::BufRead;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 
+mod error;
+// `replicant` carries the actual CRDT/account/signing implementation and is pulled in from
+// outside this tree at build time; `cargo build`/`clippy`/`cargo test` all require it to be
+// present on the module path to succeed, and haven't been run against a tree lacking it.
 mod replicant;
+use error::{Error, Result};
 use replicant::{
     create_account, create_crdt, create_crdt_info, get_random_id, Account, Applyable, CRDTInfo,
     Counter, Nat, Operation, OperationSigned, UserPubKey, UserSecKey, CRDT,
@@ -28,76 +37,326 @@ fn base64_config() -> Config {
     Config::new(CharacterSet::UrlSafe, false)
 }
 
+// Holds an advisory, exclusive lock on a `.lock` file for as long as it's alive, releasing
+// it automatically on drop. Used to stop two processes from interleaving reads and writes
+// of the same project or keystore.
+struct LockGuard {
+    file: File,
+}
+
+impl LockGuard {
+    // Opens (creating both the file and its parent directory if necessary) and exclusively
+    // locks `lock_path`. Read commands like `log` and `export` shouldn't have to assume the
+    // project directory already exists just to take its lock.
+    fn acquire_exclusive(lock_path: &Path, busy_message: &str) -> Result<LockGuard> {
+        if let Some(parent) = lock_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(lock_path)
+            .map_err(|e| Error::io(lock_path, e))?;
+
+        if file.try_lock_exclusive().is_err() {
+            return Err(Error::locked(lock_path, busy_message));
+        }
+
+        Ok(LockGuard { file })
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+// Restricts a freshly-created file to owner read/write only. `keys.json` and `.pennyop`
+// files hold key material and signed operations respectively, so they shouldn't be
+// world-readable the way `File::create`/`OpenOptions::open` leave them by default.
+#[cfg(unix)]
+fn restrict_permissions_to_owner(file: &File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_mode(0o600);
+    file.set_permissions(permissions)
+}
+
+// No equivalent ACL tweak here yet on non-Unix platforms.
+#[cfg(not(unix))]
+fn restrict_permissions_to_owner(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// A CRDT-backed counter with a signed, syncable operation log.
+#[derive(Parser)]
+#[command(name = "replicant")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a new project.
+    Init { project: String },
+    /// Open a project and interactively apply increments to it.
+    Run { project: String },
+    /// Restore all operations and print each one with its author and counter.
+    Log { project: String },
+    /// Emit this directory's signed operations to stdout, one base64 line per operation.
+    Export { project: String },
+    /// Read signed operations (as produced by `export`) from stdin and record them.
+    Import { project: String },
+}
+
 fn main() {
     let _ = ansi_term::enable_ansi_support();
-    let args: Vec<String> = env::args().collect();
+    let cli = Cli::parse();
+
+    let result = match &cli.command {
+        Command::Init { project } => cmd_init(project),
+        Command::Run { project } => cmd_run(project),
+        Command::Log { project } => cmd_log(project),
+        Command::Export { project } => cmd_export(project),
+        Command::Import { project } => cmd_import(project),
+    };
 
-    if args.len() >= 2 {
-        let project_name: &str = &args[1];
-        attempt_to_open_project(project_name);
+    if let Err(err) = result {
+        report_error(&err);
+        std::process::exit(1);
+    }
+}
+
+// Prints a user-facing message that distinguishes a transient failure (retry it, or check
+// the environment) from a reproducible one (the file itself is corrupt and needs attention).
+fn report_error(err: &Error) {
+    if err.is_transient() {
+        eprintln!("{}", Red.paint(format!("Temporarily unavailable: {}", err)));
     } else {
-        println!("Input the name of the project");
+        eprintln!("{}", Red.paint(format!("Corrupt project or keystore data: {}", err)));
     }
 }
 
-// Attempt to open the project file. If it exists, try to read the project. If it doesn't,
-// ask the user if they want to create it.
-fn attempt_to_open_project(project_name: &str) {
-    let project_basedir_str = format!("{}/", project_name);
-    let project_file_str = format!("project.penny");
-    let project_basedir = std::path::Path::new(&project_basedir_str);
-    let pennyfile_dir = project_basedir.join(std::path::Path::new(&project_file_str));
+// `<project>/` is the project's base directory, and `<project>/project.penny` holds its
+// CRDTInfo. Every subcommand starts by resolving these two paths.
+fn project_paths(project_name: &str) -> (PathBuf, PathBuf) {
+    let project_basedir = PathBuf::from(format!("{}/", project_name));
+    let pennyfile_dir = project_basedir.join("project.penny");
+    (project_basedir, pennyfile_dir)
+}
+
+// `init <project>`: create a brand new project file.
+fn cmd_init(project_name: &str) -> Result<()> {
+    let (project_basedir, pennyfile_dir) = project_paths(project_name);
 
-    match File::open(&pennyfile_dir) {
-        Ok(file) => read_project(file, project_basedir, pennyfile_dir),
-        Err(_) => create_new_project(project_name, project_basedir, pennyfile_dir),
+    if pennyfile_dir.exists() {
+        return Err(Error::corrupt(&pennyfile_dir, "a project already exists here"));
     }
+
+    let info: CRDTInfo<Nat> = create_crdt_info(Nat::from(0), get_random_id());
+    let info = bincode::serialize(&info).expect("somehow there was a serialization error");
+    fs::create_dir_all(&project_basedir).map_err(|e| Error::io(&project_basedir, e))?;
+    {
+        let mut project_file =
+            File::create(&pennyfile_dir).map_err(|e| Error::io(&pennyfile_dir, e))?;
+        restrict_permissions_to_owner(&project_file).map_err(|e| Error::io(&pennyfile_dir, e))?;
+        project_file
+            .write_all(&info)
+            .map_err(|e| Error::io(&pennyfile_dir, e))?;
+    }
+    println!("Created a new project at {:?}.", pennyfile_dir);
+    Ok(())
 }
 
-// First, we read the info file from the project file, and use the restore_operations function
-// to collect all operations that have been recorded. Then we make an account and call the `run`
-// function to ask the user how they want to change it
-fn read_project(mut file: File, project_basedir: &Path, pennyfile_dir: PathBuf) {
+// `run <project>`: read the project file, restore every recorded operation, and drop into
+// the interactive increment loop, saving any new operations on exit.
+fn cmd_run(project_name: &str) -> Result<()> {
+    let (project_basedir, pennyfile_dir) = project_paths(project_name);
+
+    // Held for the whole read -> run -> save_operations cycle below, so a second process
+    // can't read a half-written `.pennyop` or write one out from under us.
+    let _project_lock = LockGuard::acquire_exclusive(
+        &project_basedir.join(".lock"),
+        &format!(
+            "'{}' is already open in another process.",
+            project_basedir.to_string_lossy()
+        ),
+    )?;
+
     println!("Looking for a project at {:?}.", pennyfile_dir);
+    let mut file = File::open(&pennyfile_dir).map_err(|e| Error::io(&pennyfile_dir, e))?;
     let mut contents = vec![];
-    file.read_to_end(&mut contents).unwrap();
-    let project_info: CRDTInfo<Nat> = bincode::deserialize(&contents).unwrap();
+    file.read_to_end(&mut contents)
+        .map_err(|e| Error::io(&pennyfile_dir, e))?;
+    let project_info: CRDTInfo<Nat> = bincode::deserialize(&contents)
+        .map_err(|e| Error::corrupt(&pennyfile_dir, e.to_string()))?;
 
     let crdt = create_crdt(project_info);
-    let crdt = restore_operations::<Nat>(crdt, project_basedir);
+    let crdt = restore_operations::<Nat>(crdt, &project_basedir)?;
 
-    let DirectoryLevelUserInfo { pk, sk, .. } = get_keypair(&pennyfile_dir);
+    let DirectoryLevelUserInfo { pk, sk, .. } = get_keypair(&pennyfile_dir)?;
     let account = create_account(pk, sk);
 
     println!("Testing the {} CRDT", Nat::NAME);
-    run(crdt, account, project_basedir);
-}
-
-// We ask the user if they want to create a new project, and create it if so.
-fn create_new_project(project_name: &str, project_basedir: &Path, pennyfile_dir: PathBuf) {
-    print!(
-        "Couldn't open '{}'! Do you want me to create it? ",
-        project_name
-    );
-    io::stdout().flush().unwrap();
-    let mut contents = String::new();
-    io::stdin().read_line(&mut contents).unwrap();
-    if contents.trim() == "y" {
-        let info: CRDTInfo<Nat> = create_crdt_info(Nat::from(0), get_random_id());
-        let info = bincode::serialize(&info).expect("somehow there was a serialization error");
-        let _test: CRDTInfo<Nat> = bincode::deserialize(&info).unwrap();
-        fs::create_dir_all(project_basedir).unwrap();
-        {
-            let mut project_file = File::create(&pennyfile_dir).unwrap();
-            project_file.write_all(&info).unwrap();
+    run(crdt, account, &project_basedir)
+}
+
+// `log <project>`: restore every operation without applying it to a CRDT, and print each
+// one with its author's public key and counter.
+fn cmd_log(project_name: &str) -> Result<()> {
+    let (project_basedir, pennyfile_dir) = project_paths(project_name);
+
+    // `log` is read-only; check the project actually exists before `acquire_exclusive` gets
+    // a chance to materialize `project_basedir` for us while taking the lock.
+    if !pennyfile_dir.exists() {
+        return Err(Error::io(
+            &pennyfile_dir,
+            io::Error::new(io::ErrorKind::NotFound, "no project exists here - run `init` first"),
+        ));
+    }
+
+    let _project_lock = LockGuard::acquire_exclusive(
+        &project_basedir.join(".lock"),
+        &format!(
+            "'{}' is already open in another process.",
+            project_basedir.to_string_lossy()
+        ),
+    )?;
+
+    let operations = collect_all_operations::<Nat>(&project_basedir)?;
+    for (counter, operation) in operations {
+        println!(
+            "{} #{}: {:?}",
+            base64::encode_config(
+                bincode::serialize(&operation.user_pub_key).unwrap(),
+                base64_config()
+            ),
+            counter,
+            operation.data.description
+        );
+    }
+    Ok(())
+}
+
+// `export <project>`: print this directory's own signed operations to stdout, one base64
+// line per operation, so they can be piped into `import` on another machine.
+fn cmd_export(project_name: &str) -> Result<()> {
+    let (project_basedir, pennyfile_dir) = project_paths(project_name);
+
+    // `export` is read-only too - same reasoning as `cmd_log` above.
+    if !pennyfile_dir.exists() {
+        return Err(Error::io(
+            &pennyfile_dir,
+            io::Error::new(io::ErrorKind::NotFound, "no project exists here - run `init` first"),
+        ));
+    }
+
+    let _project_lock = LockGuard::acquire_exclusive(
+        &project_basedir.join(".lock"),
+        &format!(
+            "'{}' is already open in another process.",
+            project_basedir.to_string_lossy()
+        ),
+    )?;
+
+    let DirectoryLevelUserInfo { pk, .. } = get_keypair(&pennyfile_dir)?;
+    let user_dir = project_basedir.join("operations").join(base64::encode_config(
+        bincode::serialize(&pk).unwrap(),
+        base64_config(),
+    ));
+
+    if !user_dir.exists() {
+        return Ok(());
+    }
+
+    for (_, operation) in get_operations_in_path::<Nat>(&user_dir)? {
+        let encoded = base64::encode_config(
+            bincode::serialize(&operation.data).expect("somehow there was a serialization error"),
+            base64_config(),
+        );
+        println!("{}", encoded);
+    }
+    Ok(())
+}
+
+// `import <project>`: read lines produced by `export` from stdin, verify each operation's
+// signature, and write it into `operations/<pubkey>/` under the pubkey it claims to be from.
+fn cmd_import(project_name: &str) -> Result<()> {
+    let (project_basedir, _pennyfile_dir) = project_paths(project_name);
+
+    let _project_lock = LockGuard::acquire_exclusive(
+        &project_basedir.join(".lock"),
+        &format!(
+            "'{}' is already open in another process.",
+            project_basedir.to_string_lossy()
+        ),
+    )?;
+
+    let mut imported = 0;
+    for line in io::stdin().lock().lines() {
+        let line = line.map_err(|e| Error::io(&project_basedir, e))?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let operation_bytes = base64::decode_config(line, base64_config())
+            .map_err(|e| Error::corrupt(&project_basedir, format!("line isn't valid base64: {}", e)))?;
+        let operation_signed: OperationSigned<<Nat as Applyable>::Description> =
+            bincode::deserialize(&operation_bytes).map_err(|e| {
+                Error::corrupt(&project_basedir, format!("line isn't a valid operation: {}", e))
+            })?;
+
+        let author_pub_key = operation_signed.author_pub_key;
+        let counter = operation_signed.counter;
+
+        if let Err(reason) = verify_operation_signed(&author_pub_key, counter, &operation_signed) {
+            println!("{}", Red.paint(format!("Skipping an imported operation: {}", reason)));
+            continue;
+        }
+
+        let to_write_dir = project_basedir.join("operations").join(base64::encode_config(
+            bincode::serialize(&author_pub_key).unwrap(),
+            base64_config(),
+        ));
+        fs::create_dir_all(&to_write_dir).map_err(|e| Error::io(&to_write_dir, e))?;
+        let to_write_file_path = to_write_dir.join(format!("{}.pennyop", counter));
+        if to_write_file_path.exists() {
+            println!(
+                "{}",
+                Red.paint(format!(
+                    "Skipping {}: already have this operation",
+                    to_write_file_path.to_string_lossy()
+                ))
+            );
+            continue;
         }
-        println!("I created a new project at {:?}.", pennyfile_dir);
+
+        let mut file = OpenOptions::new()
+            .read(false)
+            .write(true)
+            .create(true)
+            .open(&to_write_file_path)
+            .map_err(|e| Error::io(&to_write_file_path, e))?;
+        restrict_permissions_to_owner(&file).map_err(|e| Error::io(&to_write_file_path, e))?;
+        file.write_all(
+            &bincode::serialize(&operation_signed).expect("somehow there was a serialization error"),
+        )
+        .map_err(|e| Error::io(&to_write_file_path, e))?;
+        imported += 1;
     }
+
+    println!("Imported {} operation(s).", imported);
+    Ok(())
 }
 
 // Repeatedly ask the user for a new operation. We'll apply it to the crdt. Once the user exits we'll save
 // all their operations to disk
-fn run<T: Applyable>(mut crdt: CRDT<T>, mut account: Account, project_basedir: &Path)
+fn run<T: Applyable>(mut crdt: CRDT<T>, mut account: Account, project_basedir: &Path) -> Result<()>
 where
     T: Applyable,
     T: Serialize,
@@ -112,9 +371,13 @@ where
     loop {
         println!("Current value: {}", Red.paint(format!("{}", crdt.value)));
         print!("Increment: ");
-        io::stdout().flush().unwrap();
+        io::stdout()
+            .flush()
+            .map_err(|e| Error::io(project_basedir, e))?;
         let mut increment = String::new();
-        io::stdin().read_line(&mut increment).unwrap();
+        io::stdin()
+            .read_line(&mut increment)
+            .map_err(|e| Error::io(project_basedir, e))?;
         match increment.trim().parse() {
             Ok(increment) => {
                 crdt = crdt.apply_desc(&mut account, increment);
@@ -122,108 +385,306 @@ where
             _ => break,
         }
     }
-    save_operations::<T>(crdt.flush(), project_basedir);
+    save_operations::<T>(crdt.flush(), project_basedir)
 }
 
 // Crawl through the `operations` folder to find all the user operations folders (the folder name is the user's
 // public key). Then read and apply all the operations within.
-fn restore_operations<T>(crdt: CRDT<T>, project_basedir: &Path) -> CRDT<T>
+fn restore_operations<T>(crdt: CRDT<T>, project_basedir: &Path) -> Result<CRDT<T>>
 where
     T: Applyable + Serialize + DeserializeOwned,
     T::Description: Serialize + DeserializeOwned + Ord,
 
     T: std::fmt::Debug,
     T::Description: std::fmt::Debug,
+{
+    let all_operations = collect_all_operations::<T>(project_basedir)?;
+    Ok(all_operations
+        .into_iter()
+        .map(|(_, operation)| operation)
+        .fold(crdt, CRDT::apply))
+}
+
+// Crawl through the `operations` folder and return every operation found within, each
+// paired with the counter encoded in its filename. Used both to fold operations into a
+// CRDT (`restore_operations`) and to list them without doing so (`log`).
+fn collect_all_operations<T>(
+    project_basedir: &Path,
+) -> Result<Vec<(Counter, Operation<T::Description>)>>
+where
+    T: Applyable + DeserializeOwned,
+    T::Description: DeserializeOwned,
 {
     let operation_dir = project_basedir.join("operations");
-    let mut all_operations: Vec<Operation<T::Description>> = vec![];
+    let mut all_operations = vec![];
     if operation_dir.exists() {
-        for user_entry in fs::read_dir(&operation_dir).expect(&format!(
-            "Trying to read the '{}' folder, but couldn't open it for whatever reason",
-            operation_dir.to_string_lossy()
-        )) {
-            let user_entry = user_entry.expect(&format!(
-                "ran into an error when reading an entry in the '{}' folder",
-                operation_dir.to_string_lossy()
-            ));
-
+        for user_entry in
+            fs::read_dir(&operation_dir).map_err(|e| Error::io(&operation_dir, e))?
+        {
+            let user_entry = user_entry.map_err(|e| Error::io(&operation_dir, e))?;
             let path = user_entry.path();
 
             if path.is_dir() {
-                all_operations.extend(get_operations_in_path::<T>(&path));
+                all_operations.extend(get_operations_in_path::<T>(&path)?);
             } else {
-                panic!(
-                    "I only expected directories in {}, but I came across {}, which is a file!",
-                    operation_dir.to_string_lossy(),
-                    path.to_string_lossy()
-                );
+                return Err(Error::corrupt(
+                    &operation_dir,
+                    format!(
+                        "expected only directories, but {} is a file",
+                        path.to_string_lossy()
+                    ),
+                ));
             }
         }
-        all_operations.into_iter().fold(crdt, CRDT::apply)
-    } else {
-        crdt
     }
+    Ok(all_operations)
 }
 
-// Read through a user operations directory and return a vector of all the operations within.
-fn get_operations_in_path<T>(base_path: &PathBuf) -> Vec<Operation<T::Description>>
+// Read through a user operations directory and return every operation within, paired with
+// the counter encoded in its filename. Operations whose signature doesn't check out are
+// skipped with a warning rather than treated as corrupt - see `verify_operation_signed` -
+// since that's a property of one operation, not of the file system access itself. The one
+// exception is if every operation in the folder fails verification, which is treated as a
+// hard error instead of silently returning no operations.
+fn get_operations_in_path<T>(base_path: &PathBuf) -> Result<Vec<(Counter, Operation<T::Description>)>>
 where
     T: Applyable + DeserializeOwned,
     T::Description: DeserializeOwned,
 {
     let user_pub_key: UserPubKey = {
-        let user_pub_key = base_path.components().into_iter().last().unwrap();
+        let user_pub_key = base_path
+            .components()
+            .into_iter()
+            .last()
+            .ok_or_else(|| Error::corrupt(base_path, "path had no final component"))?;
         let user_pub_key = match user_pub_key {
             std::path::Component::Normal(osstr) => osstr.to_string_lossy(),
-            _ => panic!(
-                "The last element of {} wasn't a normal part of a path",
-                base_path.to_string_lossy()
-            ),
+            _ => {
+                return Err(Error::corrupt(
+                    base_path,
+                    "the last element of the path wasn't a normal path component",
+                ))
+            }
         };
         let user_pub_key_decoded = base64::decode_config(user_pub_key.as_bytes(), base64_config())
-            .expect(&format!("{} couldn't be decoded as base64!", user_pub_key));
+            .map_err(|e| Error::corrupt(base_path, format!("folder name isn't valid base64: {}", e)))?;
 
-        bincode::deserialize(&user_pub_key_decoded).expect(&format!(
-            "{} couldn't be converted to a valid public key!",
-            user_pub_key
-        ))
+        bincode::deserialize(&user_pub_key_decoded).map_err(|e| {
+            Error::corrupt(
+                base_path,
+                format!("folder name isn't a valid public key: {}", e),
+            )
+        })?
     };
 
-    fs::read_dir(&base_path)
-        .expect(&format!(
-            "Trying to read the '{}' folder, but couldn't open it for whatever reason",
-            base_path.to_string_lossy()
+    let mut operations = vec![];
+    let mut total = 0;
+    let mut failures = 0;
+    for operation in fs::read_dir(&base_path).map_err(|e| Error::io(base_path, e))? {
+        total += 1;
+        let operation_path = operation.map_err(|e| Error::io(base_path, e))?.path();
+
+        let operation_signed: OperationSigned<T::Description> = {
+            let mut operation_bytes = vec![];
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(false)
+                .create(false)
+                .open(&operation_path)
+                .map_err(|e| Error::io(&operation_path, e))?;
+            file.read_to_end(&mut operation_bytes)
+                .map_err(|e| Error::io(&operation_path, e))?;
+            bincode::deserialize(&operation_bytes).map_err(|e| {
+                Error::corrupt(
+                    &operation_path,
+                    format!("couldn't decode into a valid operation: {}", e),
+                )
+            })?
+        };
+
+        let counter: Counter = operation_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+            .ok_or_else(|| {
+                Error::corrupt(&operation_path, "doesn't have a valid counter in its filename")
+            })?;
+
+        // The counter is encoded twice - once in the filename, once inside the signed
+        // payload - and both have to agree. We trust the filename one for bookkeeping
+        // (it's what `log`/`save_operations` key off of), but if it disagrees with what
+        // was actually signed, something wrote this file wrong and it shouldn't be trusted.
+        if counter != operation_signed.counter {
+            return Err(Error::corrupt(
+                &operation_path,
+                format!(
+                    "filename says counter #{} but the signed operation says #{}",
+                    counter, operation_signed.counter
+                ),
+            ));
+        }
+
+        match verify_operation_signed(&user_pub_key, counter, &operation_signed) {
+            Ok(()) => operations.push((
+                counter,
+                Operation {
+                    user_pub_key,
+                    data: operation_signed,
+                },
+            )),
+            Err(reason) => {
+                failures += 1;
+                eprintln!(
+                    "{}",
+                    Red.paint(format!(
+                        "Skipping {}: {}",
+                        operation_path.to_string_lossy(),
+                        reason
+                    ))
+                );
+            }
+        }
+    }
+
+    // One bad operation is just one bad operation, and worth skipping with a warning. But
+    // if every single one in this folder fails verification, that's far more likely to
+    // mean our signed-payload encoding disagrees with whatever produced these files than
+    // that every operation happens to be corrupt - and folding in zero operations without
+    // a hard error would silently erase this user's history instead of just warning about it.
+    if total > 0 && failures == total {
+        return Err(Error::corrupt(
+            base_path,
+            "every operation in this folder failed signature verification - refusing to \
+             silently drop all of this user's history",
+        ));
+    }
+
+    Ok(operations)
+}
+
+// Reconstruct the bytes that were originally signed for an operation: the counter and the
+// description it carries, using the same encoding used when the operation was created.
+fn signed_payload_bytes<D: Serialize>(counter: Counter, description: &D) -> Vec<u8> {
+    bincode::serialize(&(counter, description)).expect("somehow there was a serialization error")
+}
+
+// Checks that `operation_signed` really was produced by `folder_pub_key`: its embedded
+// author key has to match the pubkey encoded in the folder it was read from, and its
+// signature has to verify against that key. A corrupted or forged `.pennyop` file fails
+// one of these two checks instead of silently mutating the CRDT.
+fn verify_operation_signed<D: Serialize>(
+    folder_pub_key: &UserPubKey,
+    counter: Counter,
+    operation_signed: &OperationSigned<D>,
+) -> std::result::Result<(), String> {
+    if &operation_signed.author_pub_key != folder_pub_key {
+        return Err(format!(
+            "operation #{} claims a different author key than the folder it's stored in",
+            counter
+        ));
+    }
+
+    let payload = signed_payload_bytes(counter, &operation_signed.description);
+    if sign::verify_detached(&operation_signed.signature, &payload, folder_pub_key) {
+        Ok(())
+    } else {
+        Err(format!(
+            "operation #{}'s signature doesn't match its contents",
+            counter
         ))
-        .map(|operation| {
-            let operation_signed: OperationSigned<T::Description> = {
-                let mut operation_bytes = vec![];
-                let operation_path = operation.unwrap().path();
-                let mut file = OpenOptions::new()
-                    .read(true)
-                    .write(false)
-                    .create(false)
-                    .open(&operation_path)
-                    .unwrap();
-                file.read_to_end(&mut operation_bytes).unwrap();
-                bincode::deserialize(&operation_bytes).expect(&format!(
-                    "The file at {} couldn't be decoded into a valid operation!",
-                    operation_path.to_string_lossy()
-                ))
-            };
-            let operation = Operation {
-                user_pub_key,
-                data: operation_signed,
-            };
-            operation
-        })
-        .collect()
+    }
+}
+
+#[cfg(test)]
+mod verification_tests {
+    use super::*;
+
+    fn signed_operation(author_sk: &sign::SecretKey, author_pk: UserPubKey, counter: Counter, description: u64) -> OperationSigned<u64> {
+        let payload = signed_payload_bytes(counter, &description);
+        let signature = sign::sign_detached(&payload, author_sk);
+        OperationSigned {
+            description,
+            counter,
+            author_pub_key: author_pk,
+            signature,
+        }
+    }
+
+    #[test]
+    fn accepts_a_correctly_signed_operation() {
+        let (pk, sk) = sign::gen_keypair();
+        let operation_signed = signed_operation(&sk, pk, 1, 42);
+        assert!(verify_operation_signed(&pk, 1, &operation_signed).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_tampered_description() {
+        let (pk, sk) = sign::gen_keypair();
+        let mut operation_signed = signed_operation(&sk, pk, 1, 42);
+        operation_signed.description = 43;
+        assert!(verify_operation_signed(&pk, 1, &operation_signed).is_err());
+    }
+
+    #[test]
+    fn rejects_an_operation_claiming_a_different_folder_key() {
+        let (pk, sk) = sign::gen_keypair();
+        let (other_pk, _other_sk) = sign::gen_keypair();
+        let operation_signed = signed_operation(&sk, pk, 1, 42);
+        assert!(verify_operation_signed(&other_pk, 1, &operation_signed).is_err());
+    }
+
+    #[test]
+    fn rejects_a_signature_over_a_different_counter() {
+        let (pk, sk) = sign::gen_keypair();
+        let operation_signed = signed_operation(&sk, pk, 1, 42);
+        // The signature was produced over counter 1 - verifying it against counter 2
+        // should fail even though the author key and description are untouched.
+        assert!(verify_operation_signed(&pk, 2, &operation_signed).is_err());
+    }
+
+    // The tests above only check `verify_operation_signed` against its own
+    // `signed_payload_bytes` helper, which proves it's self-consistent but not that it
+    // agrees with what the rest of the crate actually signs. This one drives an operation
+    // through the real `create_account`/`apply_desc`/`flush` path, writes it to disk with
+    // `save_operations` exactly as `run` does, and reads it back with
+    // `get_operations_in_path` exactly as `log`/`export`/`restore_operations` do.
+    #[test]
+    fn verifies_an_operation_produced_by_the_real_signing_path() {
+        let (pk, sk) = sign::gen_keypair();
+        let mut account = create_account(pk, sk);
+
+        let info: CRDTInfo<Nat> = create_crdt_info(Nat::from(0), get_random_id());
+        let mut crdt = create_crdt(info);
+        crdt = crdt.apply_desc(
+            &mut account,
+            "1".parse().expect("a Nat description should parse from \"1\""),
+        );
+
+        let project_basedir =
+            std::env::temp_dir().join(format!("crdts-signing-path-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&project_basedir);
+
+        save_operations::<Nat>(crdt.flush(), &project_basedir)
+            .expect("saving operations signed by apply_desc should succeed");
+
+        let user_dir = project_basedir.join("operations").join(base64::encode_config(
+            bincode::serialize(&pk).unwrap(),
+            base64_config(),
+        ));
+        let restored = get_operations_in_path::<Nat>(&user_dir)
+            .expect("an operation signed by apply_desc should verify when read back");
+        assert_eq!(restored.len(), 1);
+
+        let _ = fs::remove_dir_all(&project_basedir);
+    }
 }
 
 // Record some operations to a user's operation folder.
 fn save_operations<T>(
     mut operations: HashMap<Counter, Operation<T::Description>>,
     project_basedir: &Path,
-) where
+) -> Result<()>
+where
     T: Applyable + Serialize,
     T::Description: Serialize,
 {
@@ -238,23 +699,28 @@ fn save_operations<T>(
             );
             project_basedir.join(std::path::Path::new(&relative_dir))
         };
-        fs::create_dir_all(&to_write_dir).expect("Failed to create directory to store operations");
+        fs::create_dir_all(&to_write_dir).map_err(|e| Error::io(&to_write_dir, e))?;
         let to_write_file_path =
             to_write_dir.join(std::path::Path::new(&format!("{}.pennyop", counter)));
         if to_write_file_path.exists() {
-            panic!("Something is messed up... I want to write to {} but it already exists. That's bad! Aborting", to_write_file_path.to_string_lossy());
+            return Err(Error::corrupt(
+                &to_write_file_path,
+                "an operation with this counter already exists - refusing to overwrite it",
+            ));
         }
         let mut file = OpenOptions::new()
             .read(false)
             .write(true)
             .create(true)
-            .open(to_write_file_path)
-            .unwrap();
+            .open(&to_write_file_path)
+            .map_err(|e| Error::io(&to_write_file_path, e))?;
+        restrict_permissions_to_owner(&file).map_err(|e| Error::io(&to_write_file_path, e))?;
         file.write_all(
             &bincode::serialize(&operation.data).expect("somehow there was a serialization error"),
         )
-        .expect("Failed to write operation");
+        .map_err(|e| Error::io(&to_write_file_path, e))?;
     }
+    Ok(())
 }
 
 // This contains the information needed to create new operations on the CRDT.
@@ -277,28 +743,202 @@ struct ComputerLevelUserInfo {
 }
 
 // This is a struct we save and restore on each run, to persistently store the user's keypairs.
-// Unfortunately, it is written in plain text. I hope this isn't too big of a deal though.
+// It is encrypted at rest - see `KeystoreEnvelope` below for the format it's actually stored in.
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 struct SavedKeys {
     computer_level_user_info: ComputerLevelUserInfo,
     dir_level_keys: HashMap<String, DirectoryLevelUserInfo>,
 }
 
+// The version of `keys.json` we're currently writing. Bumping this lets us tell old
+// formats apart from new ones if we ever change the envelope or KDF parameters.
+const KEYSTORE_VERSION: u8 = 1;
+
+// What actually gets written to `keys.json`: a passphrase-locked vault around a
+// serialized `SavedKeys`. `salt` is used to re-derive the symmetric key from the
+// passphrase with Argon2 (`pwhash`), and `nonce` is fresh for every write.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct KeystoreEnvelope {
+    version: u8,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+// Ask the user for the passphrase that locks their keystore. We don't have a way to
+// hide terminal input without pulling in another dependency, so for now this is
+// visible on screen like every other prompt in this program. The prompt itself goes to
+// stderr, not stdout, since some subcommands' stdout (`export`, in particular) is meant
+// to be piped somewhere else.
+fn prompt_passphrase(prompt: &str) -> String {
+    eprint!("{}", prompt);
+    io::stderr().flush().unwrap();
+    let mut passphrase = String::new();
+    io::stdin().read_line(&mut passphrase).unwrap();
+    passphrase.trim_end_matches(&['\n', '\r'][..]).to_string()
+}
+
+// Like `prompt_passphrase`, but used whenever we're about to lock something under a
+// passphrase for the first time (a brand new keystore, or migrating a plaintext one).
+// A typo in a single prompt there would be unrecoverable, so we ask twice and make the
+// user retry until they match.
+fn prompt_passphrase_confirmed(prompt: &str) -> String {
+    loop {
+        let first = prompt_passphrase(prompt);
+        let second = prompt_passphrase("Confirm passphrase: ");
+        if first == second {
+            return first;
+        }
+        eprintln!("Passphrases didn't match - try again.");
+    }
+}
+
+// Derive a secretbox key from a passphrase and salt using Argon2 (`pwhash`).
+fn derive_keystore_key(passphrase: &str, salt: &pwhash::Salt) -> secretbox::Key {
+    let mut key_bytes = [0u8; secretbox::KEYBYTES];
+    pwhash::derive_key(
+        &mut key_bytes,
+        passphrase.as_bytes(),
+        salt,
+        pwhash::OPSLIMIT_INTERACTIVE,
+        pwhash::MEMLIMIT_INTERACTIVE,
+    )
+    .expect("failed to derive a key from the keystore passphrase");
+    secretbox::Key(key_bytes)
+}
+
+// Encrypt a `SavedKeys` under a fresh salt and nonce, ready to be written to `keys.json`.
+fn encrypt_saved_keys(keys: &SavedKeys, passphrase: &str) -> KeystoreEnvelope {
+    let salt = pwhash::gen_salt();
+    let key = derive_keystore_key(passphrase, &salt);
+    let nonce = secretbox::gen_nonce();
+    let plaintext =
+        serde_json::to_vec(keys).expect("somehow there was a serialization error");
+    let ciphertext = secretbox::seal(&plaintext, &nonce, &key);
+
+    KeystoreEnvelope {
+        version: KEYSTORE_VERSION,
+        salt: base64::encode_config(salt.0, base64_config()),
+        nonce: base64::encode_config(nonce.0, base64_config()),
+        ciphertext: base64::encode_config(ciphertext, base64_config()),
+    }
+}
+
+// Reverse of `encrypt_saved_keys`. `keys_path` is only used to label the error if the
+// passphrase is wrong or the envelope has been tampered with - either way, it's a corrupt
+// (not transient) failure, since trying again with the same passphrase won't help.
+fn decrypt_saved_keys(
+    envelope: &KeystoreEnvelope,
+    passphrase: &str,
+    keys_path: &Path,
+) -> Result<SavedKeys> {
+    if envelope.version != KEYSTORE_VERSION {
+        return Err(Error::corrupt(
+            keys_path,
+            format!(
+                "keystore version {} isn't supported (expected {})",
+                envelope.version, KEYSTORE_VERSION
+            ),
+        ));
+    }
+
+    let salt_bytes = base64::decode_config(&envelope.salt, base64_config())
+        .map_err(|e| Error::corrupt(keys_path, format!("salt wasn't valid base64: {}", e)))?;
+    let salt = pwhash::Salt::from_slice(&salt_bytes)
+        .map_err(|_| Error::corrupt(keys_path, "salt was the wrong length"))?;
+
+    let nonce_bytes = base64::decode_config(&envelope.nonce, base64_config())
+        .map_err(|e| Error::corrupt(keys_path, format!("nonce wasn't valid base64: {}", e)))?;
+    let nonce = secretbox::Nonce::from_slice(&nonce_bytes)
+        .map_err(|_| Error::corrupt(keys_path, "nonce was the wrong length"))?;
+
+    let ciphertext = base64::decode_config(&envelope.ciphertext, base64_config())
+        .map_err(|e| Error::corrupt(keys_path, format!("ciphertext wasn't valid base64: {}", e)))?;
+
+    let key = derive_keystore_key(passphrase, &salt);
+    let plaintext = secretbox::open(&ciphertext, &nonce, &key).map_err(|_| {
+        Error::corrupt(
+            keys_path,
+            "couldn't unlock keys.json - wrong passphrase, or the file is corrupt",
+        )
+    })?;
+
+    serde_json::from_slice(&plaintext)
+        .map_err(|e| Error::corrupt(keys_path, format!("decrypted keystore wasn't a valid SavedKeys: {}", e)))
+}
+
+// Finds this platform's configuration directory for the app (without creating it).
+// Not having one counts as a transient `Error::Io`, since it reflects something about the
+// environment rather than any file actually being corrupt.
+fn project_dirs() -> Result<ProjectDirs> {
+    ProjectDirs::from("com", "PennySoftware", "Replicant").ok_or_else(|| {
+        Error::io(
+            PathBuf::new(),
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "couldn't determine the configuration directory for this platform",
+            ),
+        )
+    })
+}
+
+// Resolves (creating if necessary) the directory `keys.json` lives in, and returns
+// `keys.json`'s own path. Shared by every function below that reads or writes it.
+fn keys_json_path() -> Result<PathBuf> {
+    let config_dir = project_dirs()?.config_dir().to_path_buf();
+    eprintln!("Config directory is {:?}", &config_dir);
+    fs::create_dir_all(&config_dir).map_err(|e| Error::io(&config_dir, e))?;
+    Ok(config_dir.join("keys.json"))
+}
+
+// Whether the next passphrase we ask for is locking something for the first time (a
+// brand new keystore, or a plaintext one we're about to migrate) as opposed to unlocking
+// one that's already encrypted. Used to decide whether to confirm the passphrase.
+fn keystore_needs_new_passphrase() -> Result<bool> {
+    let keys_path = keys_json_path()?;
+    match fs::read_to_string(&keys_path) {
+        Ok(contents) => Ok(serde_json::from_str::<KeystoreEnvelope>(&contents).is_err()),
+        Err(_) => Ok(true),
+    }
+}
+
 // This takes a directory and returns a directory-level keypair. It will be unique to any directory.
-fn get_keypair(pennyfile_dir: &PathBuf) -> DirectoryLevelUserInfo {
+fn get_keypair(pennyfile_dir: &PathBuf) -> Result<DirectoryLevelUserInfo> {
     let pennyfile_dir_hash_string = {
-        let pennyfile_dir_canonicalized = fs::canonicalize(pennyfile_dir).unwrap();
+        let pennyfile_dir_canonicalized =
+            fs::canonicalize(pennyfile_dir).map_err(|e| Error::io(pennyfile_dir, e))?;
         let pennyfile_dir_bytes = pennyfile_dir_canonicalized
             .to_str()
-            .expect(
-                "The path the penny file is on isn't valid unicode, that is a requirement for now.",
-            )
+            .ok_or_else(|| {
+                Error::corrupt(
+                    pennyfile_dir,
+                    "the path the penny file is on isn't valid unicode, that is a requirement for now",
+                )
+            })?
             .as_bytes();
         let pennyfile_dir_hash = hash::hash(pennyfile_dir_bytes);
         base64::encode_config(pennyfile_dir_hash, base64_config())
     };
 
-    let mut keys = get_all_saved_keypairs();
+    let passphrase = if keystore_needs_new_passphrase()? {
+        prompt_passphrase_confirmed("Choose a new keystore passphrase: ")
+    } else {
+        prompt_passphrase("Enter your keystore passphrase: ")
+    };
+
+    // Held across both the read and the write below, so two processes can't interleave
+    // their reads and writes of keys.json.
+    let _keystore_lock = {
+        let config_dir = project_dirs()?.config_dir().to_path_buf();
+        fs::create_dir_all(&config_dir).map_err(|e| Error::io(&config_dir, e))?;
+        LockGuard::acquire_exclusive(
+            &config_dir.join(".lock"),
+            "keys.json is already being used by another process.",
+        )?
+    };
+
+    let mut keys = get_all_saved_keypairs(&passphrase)?;
+    let is_new_directory = !keys.dir_level_keys.contains_key(&pennyfile_dir_hash_string);
     let dir_keypair = keys
         .dir_level_keys
         .entry(pennyfile_dir_hash_string)
@@ -307,63 +947,135 @@ fn get_keypair(pennyfile_dir: &PathBuf) -> DirectoryLevelUserInfo {
             DirectoryLevelUserInfo { pk, sk }
         });
     let dir_keypair = dir_keypair.clone(); // I feel like there should be a way not to have to clone here
-    set_all_saved_keypairs(&keys);
-    dir_keypair
-}
-
-// This gets all saved keypairs, including the master keys.
-fn get_all_saved_keypairs() -> SavedKeys {
-    if let Some(proj_dirs) = ProjectDirs::from("com", "PennySoftware", "Replicant") {
-        let config_dir = proj_dirs.config_dir();
-        println!("Config directory is {:?}", &config_dir);
-
-        fs::create_dir_all(config_dir).expect("Failed to create configuration directory");
-        let keys_path = config_dir.join(std::path::Path::new("keys.json"));
-        match File::open(&keys_path) {
-            Ok(mut file) => {
-                let mut contents = String::new();
-                file.read_to_string(&mut contents).unwrap();
-                let keys: SavedKeys = serde_json::from_str(&contents).unwrap();
-                keys
-            }
-            Err(_) => {
-                let (pk, sk) = sign::gen_keypair();
-                let keys = SavedKeys {
-                    computer_level_user_info: ComputerLevelUserInfo {
-                        computer_pk: pk,
-                        computer_sk: sk,
-                    },
-                    dir_level_keys: HashMap::new(),
-                };
-
-                let mut file = File::create(keys_path).unwrap();
-                write!(file, "{}", serde_json::to_string(&keys).unwrap()).unwrap();
-                keys
+    if is_new_directory {
+        set_all_saved_keypairs(&keys, &passphrase)?;
+    }
+    Ok(dir_keypair)
+}
+
+// This gets all saved keypairs, including the master keys, decrypting `keys.json` with
+// the given passphrase. If `keys.json` is still in the old plaintext format, it's
+// transparently migrated to an encrypted keystore under this passphrase.
+fn get_all_saved_keypairs(passphrase: &str) -> Result<SavedKeys> {
+    let keys_path = keys_json_path()?;
+    match File::open(&keys_path) {
+        Ok(mut file) => {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)
+                .map_err(|e| Error::io(&keys_path, e))?;
+
+            match serde_json::from_str::<KeystoreEnvelope>(&contents) {
+                Ok(envelope) => decrypt_saved_keys(&envelope, passphrase, &keys_path),
+                Err(_) => {
+                    // No version header - this must be an old plaintext keys.json.
+                    // Read it as-is, then immediately re-save it encrypted.
+                    eprintln!(
+                        "keys.json is in the old plaintext format. Upgrading it to an encrypted keystore."
+                    );
+                    let keys: SavedKeys = serde_json::from_str(&contents).map_err(|e| {
+                        Error::corrupt(
+                            &keys_path,
+                            format!("neither a valid keystore nor valid plaintext SavedKeys: {}", e),
+                        )
+                    })?;
+                    set_all_saved_keypairs(&keys, passphrase)?;
+                    Ok(keys)
+                }
             }
         }
-    } else {
-        panic!("couldn't get the project directory!")
+        Err(_) => {
+            let (pk, sk) = sign::gen_keypair();
+            let keys = SavedKeys {
+                computer_level_user_info: ComputerLevelUserInfo {
+                    computer_pk: pk,
+                    computer_sk: sk,
+                },
+                dir_level_keys: HashMap::new(),
+            };
+
+            set_all_saved_keypairs(&keys, passphrase)?;
+            Ok(keys)
+        }
     }
 }
 
-// This sets the saved keypairs.
-fn set_all_saved_keypairs(keys: &SavedKeys) {
-    if let Some(proj_dirs) = ProjectDirs::from("com", "PennySoftware", "Replicant") {
-        let config_dir = proj_dirs.config_dir();
-        println!("Config directory is {:?}", &config_dir);
+// This sets the saved keypairs, encrypting them under the given passphrase before writing
+// `keys.json`.
+fn set_all_saved_keypairs(keys: &SavedKeys, passphrase: &str) -> Result<()> {
+    let keys_path = keys_json_path()?;
 
-        fs::create_dir_all(config_dir).expect("Failed to create configuration directory");
-        let keys_path = config_dir.join(std::path::Path::new("keys.json"));
+    let mut file = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(true)
+        .open(&keys_path)
+        .map_err(|e| Error::io(&keys_path, e))?;
+    restrict_permissions_to_owner(&file).map_err(|e| Error::io(&keys_path, e))?;
 
-        let mut file = OpenOptions::new()
-            .read(false)
-            .write(true)
-            .create(true)
-            .open(keys_path)
-            .unwrap();
+    let envelope = encrypt_saved_keys(keys, passphrase);
+    write!(
+        file,
+        "{}",
+        serde_json::to_string(&envelope).expect("somehow there was a serialization error")
+    )
+    .map_err(|e| Error::io(&keys_path, e))
+}
 
-        write!(file, "{}", serde_json::to_string(keys).unwrap()).unwrap();
-    } else {
-        panic!("couldn't get the project directory!")
-    };
+#[cfg(test)]
+mod keystore_tests {
+    use super::*;
+
+    fn some_saved_keys() -> SavedKeys {
+        let (computer_pk, computer_sk) = sign::gen_keypair();
+        let (dir_pk, dir_sk) = sign::gen_keypair();
+        let mut dir_level_keys = HashMap::new();
+        dir_level_keys.insert(
+            "some-directory".to_string(),
+            DirectoryLevelUserInfo { pk: dir_pk, sk: dir_sk },
+        );
+        SavedKeys {
+            computer_level_user_info: ComputerLevelUserInfo {
+                computer_pk,
+                computer_sk,
+            },
+            dir_level_keys,
+        }
+    }
+
+    #[test]
+    fn encrypt_then_decrypt_round_trips() {
+        let keys = some_saved_keys();
+        let envelope = encrypt_saved_keys(&keys, "correct horse battery staple");
+        let decrypted =
+            decrypt_saved_keys(&envelope, "correct horse battery staple", Path::new("keys.json"))
+                .expect("should decrypt with the right passphrase");
+        assert_eq!(decrypted, keys);
+    }
+
+    #[test]
+    fn decrypt_rejects_wrong_passphrase() {
+        let keys = some_saved_keys();
+        let envelope = encrypt_saved_keys(&keys, "correct horse battery staple");
+        let result = decrypt_saved_keys(&envelope, "wrong passphrase", Path::new("keys.json"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn decrypt_rejects_unknown_version() {
+        let keys = some_saved_keys();
+        let mut envelope = encrypt_saved_keys(&keys, "correct horse battery staple");
+        envelope.version = KEYSTORE_VERSION + 1;
+        let result = decrypt_saved_keys(&envelope, "correct horse battery staple", Path::new("keys.json"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn plaintext_keys_json_is_not_mistaken_for_an_envelope() {
+        let keys = some_saved_keys();
+        let plaintext = serde_json::to_string(&keys).unwrap();
+        assert!(serde_json::from_str::<KeystoreEnvelope>(&plaintext).is_err());
+
+        let round_tripped: SavedKeys = serde_json::from_str(&plaintext).unwrap();
+        assert_eq!(round_tripped, keys);
+    }
 }